@@ -15,6 +15,8 @@
 extern crate alloc;
 
 use core::cmp::{min, max};
+use core::ops::Bound;
+use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
 
 /// Representation of Chtholly Node, used to build Chtholly Tree.
@@ -36,19 +38,18 @@ impl ChthollyNode {
     }
 }
 
-/// Representation of Chtholly Tree. The nodes are sorted by their range.
+/// Representation of Chtholly Tree. The nodes are kept in a map keyed by
+/// each interval's left endpoint, so that splitting and erasing intervals
+/// around an arbitrary point is logarithmic instead of a linear shift of
+/// a vector.
 #[derive(Default)]
-pub struct ChthollyTree(Vec<ChthollyNode>);
+pub struct ChthollyTree(BTreeMap<usize, ChthollyNode>);
 
 impl ChthollyTree {
     /// Generate a new Chtholly Tree from a slice.
     pub fn from_slice(data: &[usize]) -> Self {
         Self(data.iter().enumerate().map(|(i, d)| {
-            ChthollyNode {
-                left: i,
-                right: i,
-                value: *d,
-            }
+            (i, ChthollyNode { left: i, right: i, value: *d })
         }).collect())
     }
 
@@ -56,7 +57,7 @@ impl ChthollyTree {
     /// Returns the node representing `[middle, right]`.
     pub fn split(&mut self, middle: usize) -> Option<&ChthollyNode> {
         match self.split_inner(middle) {
-            Some(index) => Some(&self.0[index]),
+            Some(key) => self.0.get(&key),
             None => None,
         }
     }
@@ -64,146 +65,121 @@ impl ChthollyTree {
     /// Set all values between `[left, right]` to be `value`, and merge them.
     /// Split nodes when necessary. Create a new node if it does not yet exist.
     pub fn merge(&mut self, left: usize, right: usize, value: usize) {
-        self.split_inner(right);
-        let index = self.split_inner(left);
-
-        match index {
-            Some(index) => {
-                self.0[index].value = value;
-                self.0[index].right = right;
+        self.split_upper(right);
+        self.split_inner(left);
 
-                while index + 1 < self.0.len() && self.0[index + 1].left <= right {
-                    self.0.remove(index + 1);
-                }
-            },
-            None => {
-                self.0.push(ChthollyNode { left, right, value });
-                self.sort_inner();
-            },
+        let covered = self.0.range(left..=right).map(|(key, _)| *key).collect::<Vec<_>>();
+        for key in covered {
+            self.0.remove(&key);
         }
+        self.0.insert(left, ChthollyNode { left, right, value });
     }
 
     /// Add `x` to all values between `[left, right]`.
     pub fn add(&mut self, left: usize, right: usize, value: usize) {
-        self.split_inner(right);
-        let start = match self.split_inner(left) {
-            Some(start) => start,
-            None => return,
-        };
-
-        for index in start..self.0.len() {
-            if self.0[index].left > right {
-                break
-            }
+        self.split_upper(right);
+        self.split_inner(left);
 
-            self.0[index].value += value;
+        for (_, node) in self.0.range_mut(left..=right) {
+            node.value += value;
         }
     }
 
     /// Find `n`-th (0-indexed) smallest `value` after `left`.
     pub fn nth(&self, left: usize, mut x: usize) -> Option<usize> {
-        let mut index = match self.0.binary_search_by(|node| {
-            node.left.cmp(&left)
-        }) {
-            Ok(index) => index,
-            Err(index) => {
-                if index > 0 {
-                    index - 1
-                } else {
-                    return None
-                }
-            },
-        };
+        let mut current = self.covering(left)?;
 
         loop {
             if x == 0 {
-                return Some(self.0[index].value)
+                return Some(current.value)
             }
 
-            let len = self.0[index].right - max(left, self.0[index].left) + 1;
+            let len = current.right - max(left, current.left) + 1;
 
             if x < len {
-                return Some(self.0[index].value)
-            }
-
-            if index + 1 >= self.0.len() {
-                return None
+                return Some(current.value)
             }
 
             x -= len;
-            index += 1;
+            current = self.after(current.left)?;
         }
     }
 
     /// Compute the sum of power between `[left, right]`.
     pub fn pow_sum(&self, left: usize, right: usize, power: u32, modulo: usize) -> usize {
-        let mut index = match self.0.binary_search_by(|node| {
-            node.left.cmp(&left)
-        }) {
-            Ok(index) => index,
-            Err(index) => {
-                if index > 0 {
-                    index - 1
-                } else {
-                    return 0
-                }
-            },
-        };
+        let mut current = self.covering(left);
 
         let mut sum = 0;
-        loop {
-            if index >= self.0.len() || self.0[index].left > right {
+        while let Some(node) = current {
+            if node.left > right {
                 break
             }
 
-            let left = max(left, self.0[index].left);
-            let right = min(right, self.0[index].right);
+            let left = max(left, node.left);
+            let right = min(right, node.right);
             let len = right - left + 1;
 
-            sum = (sum + len * (self.0[index].value.pow(power) % modulo)) % modulo;
-            index += 1;
+            sum = (sum + len * (node.value.pow(power) % modulo)) % modulo;
+            current = self.after(node.left);
         }
         sum
     }
 
-    /// Sort the tree. All public operations should already ensure
-    /// that the tree is sorted, and this function is only used when
-    /// necessary.
-    fn sort_inner(&mut self) {
-        self.0.sort_unstable_by_key(|node| node.left);
+    /// Find the node covering `x`, that is the node with the largest
+    /// `left <= x`.
+    fn covering(&self, x: usize) -> Option<&ChthollyNode> {
+        self.0.range(..=x).next_back().map(|(_, node)| node)
+    }
+
+    /// Find the node immediately after the one starting at `left`.
+    fn after(&self, left: usize) -> Option<&ChthollyNode> {
+        self.0.range((Bound::Excluded(left), Bound::Unbounded)).next().map(|(_, node)| node)
+    }
+
+    /// Split so that a node starts at `right + 1`, if `right` does not
+    /// already sit at the end of the addressable range. `right` is
+    /// frequently the inclusive upper bound supplied by a caller, so
+    /// `right == usize::MAX` is handled as "nothing past `right` to
+    /// split off" rather than overflowing.
+    fn split_upper(&mut self, right: usize) {
+        if let Some(past_right) = right.checked_add(1) {
+            self.split_inner(past_right);
+        }
     }
 
     /// Split the range between `[left, middle - 1]` and `[middle, right]`.
-    /// Returns the index representing `[middle, right]`.
+    /// Returns the key representing `[middle, right]`.
+    ///
+    /// Note the shrunk node's `right` is set to `middle - 1` so the two
+    /// halves do not overlap at `middle`. The earlier `Vec`-backed
+    /// implementation left the shrunk node's `right` at `middle` instead,
+    /// an off-by-one that `merge`/`add` papered over by always splitting
+    /// at `right` rather than `right + 1`; this map-backed version fixes
+    /// that and splits one past `right` (see `split_upper`) accordingly.
     fn split_inner(&mut self, middle: usize) -> Option<usize> {
-        let index = match self.0.binary_search_by(|node| {
-            node.left.cmp(&middle)
-        }) {
-            Ok(index) => index,
-            Err(index) => {
-                if index > 0 {
-                    index - 1
-                } else {
-                    return None
-                }
-            },
-        };
+        let found = self.covering(middle)?;
 
-        if self.0[index].left == middle {
+        if found.left == middle {
             // No need to split if left is the same as middle.
-            return Some(index)
+            return Some(found.left)
+        }
+
+        if middle > found.right {
+            // `middle` is past the interval `covering` found (e.g. one
+            // past the tree's current last index, which `split_upper`
+            // reaches whenever a caller updates through to the end).
+            // There is nothing covering `middle` to split off.
+            return None
         }
 
-        let new = ChthollyNode {
-            left: middle,
-            right: self.0[index].right,
-            value: self.0[index].value,
-        };
+        let key = found.left;
+        let right = found.right;
+        let value = found.value;
 
-        self.0.insert(index + 1, new);
-        self.0[index].right = middle;
+        self.0.get_mut(&key).expect("just found by covering").right = middle - 1;
+        self.0.insert(middle, ChthollyNode { left: middle, right, value });
 
-        Some(index + 1)
+        Some(middle)
     }
 }
 
@@ -327,4 +303,116 @@ mod tests {
     fn vector2() {
         test_vector(10, 10, 9, 9, vec![1, 1, 3, 3]);
     }
+
+    /// Sum of powers computed directly against a plain array, used as an
+    /// oracle for `pow_sum`.
+    fn oracle_pow_sum(array: &[usize], left: usize, right: usize, power: u32, modulo: usize) -> usize {
+        array[left..=right].iter().fold(0, |sum, value| {
+            (sum + value.pow(power) % modulo) % modulo
+        })
+    }
+
+    /// Differentially test the tree against a plain `Vec<usize>` oracle
+    /// that applies `add`/`merge` element-by-element. `n` and `m` are
+    /// large enough, and enough operations land, that `merge` routinely
+    /// folds several elements into one node and later splits them again
+    /// at an interior point -- the multi-element case that `vector1`/
+    /// `vector2` (with their small `n`/`m`) exercise only once or twice.
+    fn test_oracle(n: usize, m: usize, seed: usize, vmax: usize) {
+        let mut rng = CF896CRng(seed);
+
+        let mut array = random_array(n, vmax, &mut rng);
+        let ops = random_ops(n, m, vmax, &mut rng);
+
+        let mut tree = ChthollyTree::from_slice(&array);
+
+        for op in ops {
+            match op {
+                Op::Add(l, r, x) => {
+                    for value in &mut array[l - 1..=r - 1] {
+                        *value += x;
+                    }
+                    tree.add(l - 1, r - 1, x);
+                },
+                Op::Assign(l, r, x) => {
+                    for value in &mut array[l - 1..=r - 1] {
+                        *value = x;
+                    }
+                    tree.merge(l - 1, r - 1, x);
+                },
+                Op::Nth(l, _r, x) => {
+                    let expected = array[l - 1 + x - 1];
+                    let actual = tree.nth(l - 1, x - 1).expect("Oracle test failed to find n");
+                    assert_eq!(actual, expected);
+                },
+                Op::PowSum(l, r, x, y) => {
+                    let expected = oracle_pow_sum(&array, l - 1, r - 1, x as u32, y);
+                    let actual = tree.pow_sum(l - 1, r - 1, x as u32, y);
+                    assert_eq!(actual, expected);
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn oracle1() {
+        test_oracle(64, 200, 11, 9);
+    }
+
+    #[test]
+    fn oracle2() {
+        test_oracle(128, 400, 23, 5);
+    }
+
+    #[test]
+    fn split_inner_does_not_overlap_on_merged_interval() {
+        let mut tree = ChthollyTree::from_slice(&[1, 1, 1, 1, 1, 1]);
+        tree.merge(1, 4, 9);
+
+        let node = tree.split(3).expect("split point is inside the merged node");
+        assert_eq!(node.left, 3);
+        assert_eq!(node.right, 4);
+        assert_eq!(node.value, 9);
+
+        // The two halves must not overlap at `middle`: each of them
+        // contributing its own length, not one double-counting the other,
+        // is what catches the old `right = middle` off-by-one.
+        assert_eq!(tree.pow_sum(1, 2, 1, 1_000_000_000), 18);
+        assert_eq!(tree.pow_sum(3, 4, 1, 1_000_000_000), 18);
+        assert_eq!(tree.pow_sum(1, 4, 1, 1_000_000_000), 36);
+    }
+
+    #[test]
+    fn split_upper_does_not_overflow_at_usize_max() {
+        let mut tree = ChthollyTree::from_slice(&[1, 1]);
+        tree.merge(0, usize::MAX, 5);
+        tree.add(0, usize::MAX, 1);
+
+        assert_eq!(tree.nth(0, 0), Some(6));
+    }
+
+    #[test]
+    fn split_upper_at_last_index_does_not_corrupt_the_tree() {
+        let mut tree = ChthollyTree::from_slice(&[1, 1, 1, 1]);
+
+        // `right` is the tree's current last index, so `split_upper`
+        // calls `split_inner` one past it, where nothing covers the
+        // split point. That must not leave behind a node with
+        // `left > right`.
+        tree.merge(0, 3, 99);
+        assert_eq!(tree.pow_sum(0, 3, 1, 1_000_000_000), 4 * 99);
+        assert_eq!(tree.nth(0, 3), Some(99));
+        assert_eq!(tree.nth(0, 4), None);
+
+        tree.add(1, 3, 1);
+        assert_eq!(tree.pow_sum(0, 3, 1, 1_000_000_000), 99 + 3 * 100);
+        assert_eq!(tree.nth(0, 4), None);
+    }
+
+    #[test]
+    fn split_past_the_last_node_is_a_no_op() {
+        let mut tree = ChthollyTree::from_slice(&[1, 1, 1, 1]);
+        assert!(tree.split(4).is_none());
+        assert_eq!(tree.nth(0, 4), None);
+    }
 }